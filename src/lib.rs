@@ -25,22 +25,284 @@
 #[macro_use]
 extern crate lazy_static;
 
-use std::sync::Mutex;
+use std::sync::{Arc, Mutex};
 use std::collections::HashMap;
 use std::collections::hash_map::{Entry};
 
-struct Profile {
-    times: HashMap<Vec<&'static str>, std::time::Duration>,
-    counts: HashMap<Vec<&'static str>, usize>,
+/// A pluggable way to measure "how much" of something a task used.
+///
+/// The default is wall-clock time (`WallClock`), but the same call
+/// stack could just as well be measured in retired CPU instructions or
+/// any other additive quantity.  The profiler itself always uses one of
+/// the two implementations shipped here, picked at compile time (see
+/// `ActiveMeasurement`); implementing this trait for another type does
+/// not change what `push`/`report` measure.
+pub trait Measurement {
+    /// A marker for the moment a task started, produced by `start`/`now`
+    /// and consumed by `elapsed`.
+    type Instant: Copy;
+    /// The accumulated quantity this measurement produces, e.g. a
+    /// number of seconds or a raw instruction count.
+    type Value: std::ops::Add<Output = Self::Value> + Copy;
+    /// Whatever a measurement needs to keep reading progress made by the
+    /// thread that called `start`, from any other thread.  A plain
+    /// `thread_local!` can only ever answer for the thread that's
+    /// currently running, so a measurement whose source lives there
+    /// (e.g. a hardware counter) has to hand out something `Clone` and
+    /// `Send`/`Sync` instead, like an `Arc`.
+    type Handle: Clone;
+    /// Begin measuring, returning both the starting instant and a
+    /// `Handle` that can later be passed to `now` from any
+    /// thread to read how far this measurement has progressed.
+    fn start() -> (Self::Instant, Self::Handle);
+    /// Read the current instant through `handle`, safe to call from any
+    /// thread, not just the one that called `start`.
+    fn now(handle: &Self::Handle) -> Self::Instant;
+    /// The `Value` elapsed between two instants produced by `start`/`now`.
+    fn elapsed(start: Self::Instant, now: Self::Instant) -> Self::Value;
+    /// Render a `Value` for humans, e.g. `"12.34 ms"`.
+    fn format(value: Self::Value) -> String;
+}
+
+/// The default `Measurement`: elapsed wall-clock time, in seconds.
+pub struct WallClock;
+
+impl Measurement for WallClock {
+    type Instant = std::time::Instant;
+    type Value = f64;
+    // Wall-clock time is the same no matter which thread reads it, so
+    // there's nothing thread-specific to hand out here.
+    type Handle = ();
+    fn start() -> (std::time::Instant, ()) {
+        (std::time::Instant::now(), ())
+    }
+    fn now(_handle: &()) -> std::time::Instant {
+        std::time::Instant::now()
+    }
+    fn elapsed(start: std::time::Instant, now: std::time::Instant) -> f64 {
+        if now > start {
+            duration_to_f64(now.duration_since(start))
+        } else {
+            0.0
+        }
+    }
+    fn format(value: f64) -> String {
+        pretty_time(value)
+    }
+}
+
+/// A `Measurement` that counts retired CPU instructions instead of
+/// wall-clock time, via the `perf-event` crate.  This gives reproducible
+/// numbers that don't jitter with scheduler noise, at the cost of only
+/// working on Linux.
+#[cfg(all(target_os = "linux", feature = "hardware-counter"))]
+pub struct HardwareCounter;
+
+#[cfg(all(target_os = "linux", feature = "hardware-counter"))]
+thread_local! {
+    // The `perf_event::Counter` a thread opens for itself is wrapped in
+    // an `Arc<Mutex<_>>` (rather than a bare value) so that `start` can
+    // hand a clone of it out as a `Handle`: the underlying fd tracks
+    // whichever thread/cpu it was opened against regardless of which
+    // thread calls `read` on it, so reading it from another thread (e.g.
+    // while flushing a still-open task from `timings()`) is correct,
+    // unlike reaching for *this* thread's own `thread_local!` counter.
+    static INSTRUCTION_COUNTER: Arc<Mutex<perf_event::Counter>> = Arc::new(Mutex::new({
+        let mut counter = perf_event::Builder::new()
+            .kind(perf_event::events::Hardware::INSTRUCTIONS)
+            .build()
+            .expect("failed to open a hardware performance counter");
+        counter.enable().expect("failed to enable hardware performance counter");
+        counter
+    }));
+}
+
+#[cfg(all(target_os = "linux", feature = "hardware-counter"))]
+impl Measurement for HardwareCounter {
+    type Instant = u64;
+    type Value = f64;
+    type Handle = Arc<Mutex<perf_event::Counter>>;
+    fn start() -> (u64, Self::Handle) {
+        let handle = INSTRUCTION_COUNTER.with(|c| c.clone());
+        let now = Self::now(&handle);
+        (now, handle)
+    }
+    fn now(handle: &Self::Handle) -> u64 {
+        handle.lock().unwrap().read().unwrap_or(0)
+    }
+    fn elapsed(start: u64, now: u64) -> f64 {
+        now.saturating_sub(start) as f64
+    }
+    fn format(value: f64) -> String {
+        format!("{:.0} instructions", value)
+    }
+}
+
+/// The measurement backend actually used by `push`/`replace`/`report`.
+///
+/// This is a compile-time choice, the same way Criterion picks a
+/// `Measurement` for a benchmark: by default it's `WallClock`; building
+/// with the `hardware-counter` feature on Linux switches it to
+/// `HardwareCounter` instead.
+#[cfg(not(all(target_os = "linux", feature = "hardware-counter")))]
+type ActiveMeasurement = WallClock;
+#[cfg(all(target_os = "linux", feature = "hardware-counter"))]
+type ActiveMeasurement = HardwareCounter;
+
+type MInstant = <ActiveMeasurement as Measurement>::Instant;
+type MValue = <ActiveMeasurement as Measurement>::Value;
+type MHandle = <ActiveMeasurement as Measurement>::Handle;
+type TimesMap = HashMap<Vec<&'static str>, MValue>;
+type CountsMap = HashMap<Vec<&'static str>, usize>;
+type AllocsMap = HashMap<Vec<&'static str>, isize>;
+
+/// A counting `#[global_allocator]` used by the `alloc-profiling` feature
+/// to track net bytes allocated per thread, the same way `LocalProfile`
+/// tracks elapsed time per thread.  It wraps `std::alloc::System`, so it
+/// behaves exactly like the default allocator other than recording a
+/// running total.  The count is kept in a per-thread `AtomicIsize`
+/// rather than a single shared atomic, since a shared counter would
+/// attribute bytes allocated concurrently on *other* threads to whatever
+/// task happens to be open on this one; the thread-local holds a leaked
+/// `&'static AtomicIsize` (rather than a bare `Cell`) so a reference to it
+/// can be handed out and read from another thread, much as
+/// `HardwareCounter`'s handle is.
+#[cfg(feature = "alloc-profiling")]
+mod alloc_tracking {
+    use std::alloc::{GlobalAlloc, Layout, System};
+    use std::sync::atomic::{AtomicIsize, Ordering};
+
+    thread_local! {
+        // Leaked via `System` directly, bypassing `CountingAllocator`
+        // below: lazily initializing this thread-local with something
+        // that itself went through the instrumented global allocator
+        // would reenter `alloc` while `ALLOCATED` is still being set up
+        // (and has, in practice, crashed rather than degraded
+        // gracefully). The one-`AtomicIsize`-per-thread leak this causes
+        // is permanent but negligible.
+        static ALLOCATED: &'static AtomicIsize = unsafe {
+            let layout = Layout::new::<AtomicIsize>();
+            let ptr = System.alloc(layout) as *mut AtomicIsize;
+            if ptr.is_null() {
+                std::alloc::handle_alloc_error(layout);
+            }
+            ptr.write(AtomicIsize::new(0));
+            &*ptr
+        };
+    }
+
+    pub struct CountingAllocator;
+
+    unsafe impl GlobalAlloc for CountingAllocator {
+        unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+            let ptr = System.alloc(layout);
+            if !ptr.is_null() {
+                let _ = ALLOCATED.try_with(|c| c.fetch_add(layout.size() as isize, Ordering::Relaxed));
+            }
+            ptr
+        }
+        unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+            System.dealloc(ptr, layout);
+            let _ = ALLOCATED.try_with(|c| c.fetch_sub(layout.size() as isize, Ordering::Relaxed));
+        }
+    }
+
+    #[global_allocator]
+    static GLOBAL: CountingAllocator = CountingAllocator;
+
+    /// The calling thread's allocation counter, cheap to hand out and
+    /// safe to read (via `AtomicIsize::load`) from any thread afterward.
+    pub fn current_counter() -> &'static AtomicIsize {
+        ALLOCATED.with(|c| *c)
+    }
+}
+
+/// A handle to a thread's live allocation counter: cheap to copy, and
+/// safe to read from any thread.  Without the `alloc-profiling` feature
+/// this is a zero-sized no-op, so the rest of the crate can use it
+/// unconditionally.
+#[cfg(feature = "alloc-profiling")]
+type AllocCounterHandle = &'static std::sync::atomic::AtomicIsize;
+#[cfg(feature = "alloc-profiling")]
+fn current_alloc_counter() -> AllocCounterHandle {
+    alloc_tracking::current_counter()
+}
+#[cfg(feature = "alloc-profiling")]
+fn read_alloc_counter(handle: &AllocCounterHandle) -> isize {
+    handle.load(std::sync::atomic::Ordering::Relaxed)
+}
+
+#[cfg(not(feature = "alloc-profiling"))]
+type AllocCounterHandle = ();
+#[cfg(not(feature = "alloc-profiling"))]
+fn current_alloc_counter() -> AllocCounterHandle {}
+#[cfg(not(feature = "alloc-profiling"))]
+fn read_alloc_counter(_handle: &AllocCounterHandle) -> isize {
+    0
+}
+
+/// The stack and clock for a single thread's in-progress profiling.
+///
+/// Each thread keeps its own `LocalProfile`, so two threads pushing and
+/// popping tasks concurrently never corrupt each other's call stack.
+struct LocalProfile {
     stack: Vec<&'static str>,
-    started: std::time::Instant,
+    started: MInstant,
+    handle: MHandle,
+    started_bytes: isize,
+    alloc_counter: AllocCounterHandle,
+}
+
+impl LocalProfile {
+    #[allow(clippy::let_unit_value)] // `alloc_counter` is `()` without `alloc-profiling`
+    fn new() -> LocalProfile {
+        let (started, handle) = ActiveMeasurement::start();
+        let alloc_counter = current_alloc_counter();
+        let started_bytes = read_alloc_counter(&alloc_counter);
+        LocalProfile {
+            stack: Vec::new(),
+            started,
+            handle,
+            started_bytes,
+            alloc_counter,
+        }
+    }
+}
+
+/// Deregisters its `LocalProfile` from `REGISTRY` when the owning thread
+/// exits, so `REGISTRY` doesn't grow one stale entry per thread forever
+/// in a process that churns through many short-lived profiled threads.
+struct RegisteredLocal(Arc<Mutex<LocalProfile>>);
+
+impl Drop for RegisteredLocal {
+    fn drop(&mut self) {
+        REGISTRY.lock().unwrap().retain(|local| !Arc::ptr_eq(local, &self.0));
+    }
+}
+
+thread_local! {
+    /// Every thread that ever calls `push` registers its `LocalProfile`
+    /// in `REGISTRY` (below) so that `report` and `clear` can reach into
+    /// other threads' stacks, which a plain (non-shared) thread-local
+    /// cannot do on its own.
+    static LOCAL: RegisteredLocal = {
+        let local = Arc::new(Mutex::new(LocalProfile::new()));
+        REGISTRY.lock().unwrap().push(local.clone());
+        RegisteredLocal(local)
+    };
+}
+
+struct Profile {
+    times: TimesMap,
+    counts: CountsMap,
+    allocs: AllocsMap,
 }
 
-fn add_to_map<K: std::hash::Hash + std::cmp::Eq>(m: &mut HashMap<K, std::time::Duration>,
-                                                 k: K, d: std::time::Duration) {
+fn add_to_map<K: std::hash::Hash + std::cmp::Eq, V: std::ops::Add<Output = V> + Copy>(
+    m: &mut HashMap<K, V>, k: K, d: V) {
     match m.entry(k) {
         Entry::Occupied(mut o) => {
-            *o.get_mut() += d;
+            *o.get_mut() = *o.get() + d;
         },
         Entry::Vacant(v) => {
             v.insert(d);
@@ -64,33 +326,136 @@ impl Profile {
         Profile {
             times: HashMap::new(),
             counts: HashMap::new(),
-            started: std::time::Instant::now(),
-            stack: Vec::new(),
+            allocs: HashMap::new(),
         }
     }
-    fn add_time(&mut self, now: std::time::Instant) {
-        if now > self.started {
-            let d = now.duration_since(self.started);
-            add_to_map(&mut self.times, self.stack.clone(), d);
-        }
+    /// Record the value accumulated between `started` and `now` (both
+    /// produced against `handle`) under `stack`.  `now`/`now_bytes` are
+    /// passed in, rather than read here via `ActiveMeasurement::now`,
+    /// since the caller may be flushing a *different* thread's
+    /// `LocalProfile` (see `timings`) and only `handle`/`alloc_counter`,
+    /// not a bare `thread_local!`, can be read correctly from there.
+    fn add_time(&mut self, stack: &[&'static str], started: MInstant, now: MInstant,
+                started_bytes: isize, now_bytes: isize) {
+        let d = ActiveMeasurement::elapsed(started, now);
+        add_to_map(&mut self.times, stack.to_owned(), d);
+        add_to_map(&mut self.allocs, stack.to_owned(), now_bytes - started_bytes);
     }
 }
 
 lazy_static! {
     static ref PROFILE: Mutex<Profile> = Mutex::new(Profile::new());
+    static ref REGISTRY: Mutex<Vec<Arc<Mutex<LocalProfile>>>> = Mutex::new(Vec::new());
+    static ref FILTER: Mutex<Filter> = Mutex::new(Filter::default());
+}
+
+/// Restricts what `push`/`replace` actually record, so you can profile a
+/// hot loop without being drowned in thousands of sub-millisecond frames.
+///
+/// A default (empty) `Filter` preserves today's behavior: every task is
+/// recorded, at any depth, no matter how short.
+///
+/// # Example
+///
+/// ```
+/// crude_profiler::set_filter(crude_profiler::Filter::new()
+///                             .with_max_depth(2)
+///                             .with_longer_than(std::time::Duration::from_millis(1)));
+/// ```
+#[derive(Clone)]
+pub struct Filter {
+    names: Option<std::collections::HashSet<&'static str>>,
+    max_depth: usize,
+    longer_than: std::time::Duration,
+}
+
+impl Default for Filter {
+    fn default() -> Filter {
+        Filter {
+            names: None,
+            max_depth: usize::MAX,
+            longer_than: std::time::Duration::from_secs(0),
+        }
+    }
+}
+
+impl Filter {
+    /// Create a `Filter` that allows everything, to be narrowed with the
+    /// `with_*` methods below.
+    pub fn new() -> Filter {
+        Filter::default()
+    }
+    /// Only record tasks whose name is in `names`.  Tasks that are
+    /// filtered out still run, but their time folds into their parent.
+    pub fn with_names<I: IntoIterator<Item = &'static str>>(mut self, names: I) -> Filter {
+        self.names = Some(names.into_iter().collect());
+        self
+    }
+    /// Stop recording once the call stack is `max_depth` deep.
+    pub fn with_max_depth(mut self, max_depth: usize) -> Filter {
+        self.max_depth = max_depth;
+        self
+    }
+    /// Drop any stack whose accumulated time is below `longer_than` out
+    /// of `report()`, folding it into a synthetic `"<...>"` sibling.
+    pub fn with_longer_than(mut self, longer_than: std::time::Duration) -> Filter {
+        self.longer_than = longer_than;
+        self
+    }
+    fn allows(&self, task: &'static str, depth: usize) -> bool {
+        depth < self.max_depth && self.names.as_ref().is_none_or(|n| n.contains(task))
+    }
+}
+
+/// Restrict what future `push`/`replace` calls record.  See `Filter`.
+pub fn set_filter(filter: Filter) {
+    *FILTER.lock().unwrap() = filter;
 }
 
 /// A `Guard` causes a task to end when it is dropped.
+///
+/// If the task was excluded by the current `Filter`, the `Guard` is a
+/// no-op: dropping it does nothing, so its time folds into whichever
+/// task is still open above it.  Calling `replace` on such a `Guard`
+/// pushes the new task (if the `Filter` allows it) without popping
+/// anything, and the `Guard` then ends that task when dropped.
+///
+/// A `Guard` is tied to the thread that created it: it pops (or
+/// replaces) a frame on *that* thread's stack, which only makes sense on
+/// the thread that pushed it. Dropping or `replace`-ing it from another
+/// thread panics rather than silently popping an unrelated frame off
+/// that other thread's stack.
 pub struct Guard {
+    active: std::cell::Cell<bool>,
+    owner: std::thread::ThreadId,
+}
+
+impl Guard {
+    fn assert_owning_thread(&self) {
+        assert_eq!(
+            self.owner,
+            std::thread::current().id(),
+            "crude_profiler::Guard must be dropped (or replaced) on the thread that created it"
+        );
+    }
 }
 
 impl Drop for Guard {
     fn drop(&mut self) {
-        let now = std::time::Instant::now();
-        let mut m = PROFILE.lock().unwrap();
-        m.add_time(now);
-        m.stack.pop();
-        m.started = std::time::Instant::now();
+        if !self.active.get() {
+            return;
+        }
+        self.assert_owning_thread();
+        LOCAL.with(|local| {
+            let mut m = PROFILE.lock().unwrap();
+            let mut local = local.0.lock().unwrap();
+            let now = ActiveMeasurement::now(&local.handle);
+            let now_bytes = read_alloc_counter(&local.alloc_counter);
+            m.add_time(&local.stack, local.started, now, local.started_bytes, now_bytes);
+            local.stack.pop();
+            local.started = now;
+            local.started_bytes = now_bytes;
+        });
     }
 }
 
@@ -107,14 +472,28 @@ impl Guard {
     /// println!("{}", crude_profiler::report());
     /// ```
     pub fn replace(&self, task: &'static str) {
-        let now = std::time::Instant::now();
-        let mut m = PROFILE.lock().unwrap();
-        m.add_time(now);
-        m.stack.pop();
-        m.stack.push(task);
-        let st = m.stack.clone();
-        increment_map(&mut m.counts, st, 1);
-        m.started = std::time::Instant::now();
+        self.assert_owning_thread();
+        LOCAL.with(|local| {
+            let mut m = PROFILE.lock().unwrap();
+            let mut local = local.0.lock().unwrap();
+            let now = ActiveMeasurement::now(&local.handle);
+            let now_bytes = read_alloc_counter(&local.alloc_counter);
+            m.add_time(&local.stack, local.started, now, local.started_bytes, now_bytes);
+            if self.active.get() {
+                local.stack.pop();
+            }
+            let depth = local.stack.len();
+            if FILTER.lock().unwrap().allows(task, depth) {
+                local.stack.push(task);
+                let st = local.stack.clone();
+                increment_map(&mut m.counts, st, 1);
+                self.active.set(true);
+            } else {
+                self.active.set(false);
+            }
+            local.started = now;
+            local.started_bytes = now_bytes;
+        });
     }
 }
 
@@ -128,30 +507,59 @@ impl Guard {
 /// println!("{}", crude_profiler::report());
 /// ```
 pub fn push(task: &'static str) -> Guard {
-    let now = std::time::Instant::now();
-    let mut m = PROFILE.lock().unwrap();
-    m.add_time(now);
-    m.stack.push(task);
-    let st = m.stack.clone();
-    increment_map(&mut m.counts, st, 1);
-    m.started = std::time::Instant::now();
-    Guard {}
+    LOCAL.with(|local| {
+        let mut m = PROFILE.lock().unwrap();
+        let mut local = local.0.lock().unwrap();
+        let now = ActiveMeasurement::now(&local.handle);
+        let now_bytes = read_alloc_counter(&local.alloc_counter);
+        m.add_time(&local.stack, local.started, now, local.started_bytes, now_bytes);
+        let depth = local.stack.len();
+        let active = FILTER.lock().unwrap().allows(task, depth);
+        if active {
+            local.stack.push(task);
+            let st = local.stack.clone();
+            increment_map(&mut m.counts, st, 1);
+        }
+        local.started = now;
+        local.started_bytes = now_bytes;
+        Guard { active: std::cell::Cell::new(active), owner: std::thread::current().id() }
+    })
+}
+
+/// Run `f` with `task` pushed onto the stack, popping it again once `f`
+/// returns — a scoped alternative to holding onto the `Guard`
+/// yourself.
+///
+/// # Example
+///
+/// ```
+/// let result = crude_profiler::profile("test one", || 1 + 1);
+/// assert_eq!(result, 2);
+/// ```
+pub fn profile<T>(task: &'static str, f: impl FnOnce() -> T) -> T {
+    let _guard = push(task);
+    f()
 }
 
-/// Forget any prior timings.
+/// Forget any prior timings, on every thread that has profiled so far.
 pub fn clear() {
     let mut m = PROFILE.lock().unwrap();
     m.times = HashMap::new();
     m.counts = HashMap::new();
-    m.stack = Vec::new();
-    m.started = std::time::Instant::now();
+    m.allocs = HashMap::new();
+    for local in REGISTRY.lock().unwrap().iter() {
+        let mut local = local.lock().unwrap();
+        local.stack = Vec::new();
+        local.started = ActiveMeasurement::now(&local.handle);
+        local.started_bytes = read_alloc_counter(&local.alloc_counter);
+    }
 }
 
 fn pretty_stack(v: &Vec<&'static str>) -> String {
     let mut out = String::new();
     for s in v {
         out.push_str(s);
-        out.push_str(":");
+        out.push(':');
     }
     out
 }
@@ -174,70 +582,411 @@ fn pretty_time(t: f64) -> String {
     }
 }
 
-/// Create a string that holds a report of time used.  This is
-/// currently the *only* way to extract timings data, so obviously it
-/// isn't very automation-friendly.
-pub fn report() -> String {
-    let now = std::time::Instant::now();
+#[cfg(feature = "alloc-profiling")]
+fn pretty_bytes(bytes: isize) -> String {
+    let sign = if bytes < 0 { "-" } else { "+" };
+    let b = bytes.unsigned_abs() as f64;
+    if b < 1024.0 {
+        format!("{}{:.0} B", sign, b)
+    } else if b < 1024.0*1024.0 {
+        format!("{}{:.1} KB", sign, b/1024.0)
+    } else if b < 1024.0*1024.0*1024.0 {
+        format!("{}{:.1} MB", sign, b/(1024.0*1024.0))
+    } else {
+        format!("{}{:.2} GB", sign, b/(1024.0*1024.0*1024.0))
+    }
+}
+
+/// The ` +N MB`-style suffix `report()` appends to each line.  Without
+/// the `alloc-profiling` feature `bytes` is always `0`, so this is a
+/// no-op that keeps the report unchanged.
+#[cfg(feature = "alloc-profiling")]
+fn format_alloc_suffix(bytes: isize) -> String {
+    format!(" {}", pretty_bytes(bytes))
+}
+#[cfg(not(feature = "alloc-profiling"))]
+fn format_alloc_suffix(_bytes: isize) -> String {
+    String::new()
+}
+
+/// Drop any stack whose accumulated value is below `longer_than` out of
+/// `times`/`counts`/`allocs`, folding it into a synthetic `"<...>"`
+/// sibling under its parent so that totals are unaffected.
+fn fold_short_stacks(times: &TimesMap, counts: &CountsMap, allocs: &AllocsMap,
+                      longer_than: std::time::Duration)
+                      -> (TimesMap, CountsMap, AllocsMap) {
+    let longer_than = duration_to_f64(longer_than);
+    let mut folded_times = HashMap::new();
+    let mut folded_counts = HashMap::new();
+    let mut folded_allocs = HashMap::new();
+    for (k, &d) in times.iter() {
+        let key = if !k.is_empty() && d < longer_than {
+            let mut parent = k[..k.len() - 1].to_vec();
+            parent.push("<...>");
+            parent
+        } else {
+            k.clone()
+        };
+        add_to_map(&mut folded_times, key.clone(), d);
+        increment_map(&mut folded_counts, key.clone(), counts.get(k).cloned().unwrap_or(0));
+        add_to_map(&mut folded_allocs, key, allocs.get(k).cloned().unwrap_or(0));
+    }
+    (folded_times, folded_counts, folded_allocs)
+}
+
+/// One aggregated call-stack entry, as exposed by `timings()`.
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+#[derive(Debug, Clone)]
+pub struct TimingEntry {
+    /// The full call stack this entry was recorded under, outermost
+    /// task first.
+    pub stack: Vec<&'static str>,
+    /// The total value (e.g. seconds) accumulated under this exact stack.
+    pub total: f64,
+    /// How many times this exact stack was recorded.
+    pub count: usize,
+    /// Net bytes allocated while this exact stack was on top, under the
+    /// `alloc-profiling` feature.  Always `0` without that feature.
+    pub allocated: isize,
+}
+
+impl TimingEntry {
+    /// The mean value per call: `total / count`.
+    pub fn mean(&self) -> f64 {
+        self.total / self.count as f64
+    }
+}
+
+/// A snapshot of the raw per-stack timing data, for automation that
+/// wants more than a formatted report string.  `report()` is just a
+/// formatter built on top of this.
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+#[derive(Debug, Clone)]
+pub struct Timings {
+    entries: Vec<TimingEntry>,
+}
+
+impl Timings {
+    /// All recorded entries, in no particular order.
+    pub fn entries(&self) -> &[TimingEntry] {
+        &self.entries
+    }
+    /// The total value recorded across every stack.
+    pub fn total(&self) -> f64 {
+        self.entries.iter().map(|e| e.total).sum()
+    }
+    /// The net bytes allocated across every stack, under the
+    /// `alloc-profiling` feature.  Always `0` without that feature.
+    pub fn total_allocated(&self) -> isize {
+        self.entries.iter().map(|e| e.allocated).sum()
+    }
+    /// Only the entries whose stack starts with `prefix`.
+    pub fn by_prefix(&self, prefix: &[&'static str]) -> Vec<&TimingEntry> {
+        self.entries.iter().filter(|e| e.stack.starts_with(prefix)).collect()
+    }
+    /// Serialize the timings to a JSON string, so they can be dumped to
+    /// a file and diffed across runs.
+    #[cfg(feature = "serde")]
+    pub fn to_json(&self) -> serde_json::Result<String> {
+        serde_json::to_string(&self.entries)
+    }
+}
+
+/// Extract the raw timing data recorded so far.  Unlike `report`, this
+/// is automation-friendly: the data is structured, not formatted.
+pub fn timings() -> Timings {
     let mut m = PROFILE.lock().unwrap();
-    m.add_time(now);
+    for local in REGISTRY.lock().unwrap().iter() {
+        let mut local = local.lock().unwrap();
+        // `now`/`now_bytes` are read through `local.handle`/
+        // `local.alloc_counter`, not the calling thread's own
+        // thread-local counters, since this may be flushing a task that's
+        // still open on a different thread than the one calling
+        // `timings`.
+        let now = ActiveMeasurement::now(&local.handle);
+        let now_bytes = read_alloc_counter(&local.alloc_counter);
+        m.add_time(&local.stack, local.started, now, local.started_bytes, now_bytes);
+        // Reset the in-flight baseline to what was just flushed, the
+        // same way `clear()` does, so a task that's still open when
+        // `timings`/`report` is called again only contributes the
+        // *new* interval since this flush, rather than re-adding the
+        // ever-growing interval since it was originally pushed.
+        local.started = now;
+        local.started_bytes = now_bytes;
+    }
+    let longer_than = FILTER.lock().unwrap().longer_than;
+    let (times, counts, allocs) = fold_short_stacks(&m.times, &m.counts, &m.allocs, longer_than);
+    let entries = times.into_iter().map(|(stack, total)| {
+        let count = counts[&stack];
+        let allocated = allocs.get(&stack).cloned().unwrap_or(0);
+        TimingEntry { stack, total, count, allocated }
+    }).collect();
+    Timings { entries }
+}
+
+/// Create a string that holds a report of time used.
+pub fn report() -> String {
+    let timings = timings();
+    let times: TimesMap = timings.entries.iter().map(|e| (e.stack.clone(), e.total)).collect();
+    let counts: CountsMap = timings.entries.iter().map(|e| (e.stack.clone(), e.count)).collect();
+    let allocs: AllocsMap = timings.entries.iter().map(|e| (e.stack.clone(), e.allocated)).collect();
     let mut out = String::new();
-    let mut total_time = std::time::Duration::from_secs(0);
-    for &v in m.times.values() {
+    let mut total_time: MValue = 0.0;
+    for &v in times.values() {
         total_time += v;
     }
-    let mut keys: Vec<_> = m.times.keys().collect();
+    let mut keys: Vec<_> = times.keys().collect();
     keys.sort();
-    let mut cum: HashMap<&'static str, std::time::Duration> = HashMap::new();
+    let mut cum: HashMap<&'static str, MValue> = HashMap::new();
     let mut cumcount: HashMap<&'static str, usize> = HashMap::new();
+    let mut cumalloc: HashMap<&'static str, isize> = HashMap::new();
     for &k in keys.iter() {
         for &s in k.iter() {
-            add_to_map(&mut cum, s, m.times[k]);
-            increment_map(&mut cumcount, s, m.counts[k]);
+            add_to_map(&mut cum, s, times[k]);
+            increment_map(&mut cumcount, s, counts[k]);
+            add_to_map(&mut cumalloc, s, allocs[k]);
         }
     }
     let mut shortkeys: Vec<_> = cum.keys().collect();
-    shortkeys.sort_by_key(|&s| cum[s]);
+    shortkeys.sort_by(|&a, &b| cum[a].partial_cmp(&cum[b]).unwrap());
     shortkeys.reverse();
-    let total_f64 = duration_to_f64(total_time);
+    let total_f64 = total_time;
     for s in shortkeys {
-        let mut ways: HashMap<Vec<&'static str>, std::time::Duration> = HashMap::new();
-        let mut wayscount: HashMap<Vec<&'static str>, usize> = HashMap::new();
+        let mut ways: TimesMap = HashMap::new();
+        let mut wayscount: CountsMap = HashMap::new();
+        let mut waysalloc: AllocsMap = HashMap::new();
         for &k in keys.iter().filter(|&k| k.contains(s)) {
             let mut vv = Vec::from(k.split(|&ss| ss == *s).next().unwrap());
             vv.push(s);
-            add_to_map(&mut ways, vv.clone(), m.times[k]);
-            increment_map(&mut wayscount, vv, m.counts[k]);
+            add_to_map(&mut ways, vv.clone(), times[k]);
+            increment_map(&mut wayscount, vv.clone(), counts[k]);
+            add_to_map(&mut waysalloc, vv, allocs[k]);
         }
         let mut waykeys: Vec<_> = ways.keys().collect();
-        waykeys.sort_by_key(|&k| ways[k]);
+        waykeys.sort_by(|&a, &b| ways[a].partial_cmp(&ways[b]).unwrap());
         waykeys.reverse();
-        let percent = 100.0*duration_to_f64(cum[s])/total_f64;
+        let percent = 100.0*cum[s]/total_f64;
         if waykeys.len() > 1 {
-            out.push_str(&format!("{:4.1}% {} {} ({}, {})\n",
-                                  percent, &s,
-                                  pretty_time(duration_to_f64(cum[s])), cumcount[s],
-                                  pretty_time(duration_to_f64(cum[s])/cumcount[s] as f64)));
+            out.push_str(&format!("{:4.1}% {} {} ({}, {}){}\n",
+                                  percent, s,
+                                  ActiveMeasurement::format(cum[s]), cumcount[s],
+                                  ActiveMeasurement::format(cum[s]/cumcount[s] as f64),
+                                  format_alloc_suffix(cumalloc[s])));
             for &k in waykeys.iter().filter(|&k| k.contains(s)) {
-                let percent = 100.0*duration_to_f64(ways[k])/total_f64;
-                out.push_str(&format!("      {:4.1}% {} {} ({}, {})\n",
-                                      percent, &pretty_stack(k),
-                                      pretty_time(duration_to_f64(ways[k])),
+                let percent = 100.0*ways[k]/total_f64;
+                out.push_str(&format!("      {:4.1}% {} {} ({}, {}){}\n",
+                                      percent, pretty_stack(k),
+                                      ActiveMeasurement::format(ways[k]),
                                       wayscount[k],
-                                      pretty_time(duration_to_f64(ways[k])/wayscount[k] as f64)));
+                                      ActiveMeasurement::format(ways[k]/wayscount[k] as f64),
+                                      format_alloc_suffix(waysalloc[k])));
             }
         } else {
-            out.push_str(&format!("{:4.1}% {} {} ({}, {})\n", percent,
-                                  &pretty_stack(waykeys[0]),
-                                  pretty_time(duration_to_f64(cum[s])),
+            out.push_str(&format!("{:4.1}% {} {} ({}, {}){}\n", percent,
+                                  pretty_stack(waykeys[0]),
+                                  ActiveMeasurement::format(cum[s]),
                                   cumcount[s],
-                                  pretty_time(duration_to_f64(cum[s])/cumcount[s] as f64)));
+                                  ActiveMeasurement::format(cum[s]/cumcount[s] as f64),
+                                  format_alloc_suffix(cumalloc[s])));
         }
     }
     // out.push_str(&format!("{:?}", m.times));
     out
 }
 
+/// Siblings that individually account for less than this fraction of
+/// their parent's value are collapsed into a single `"(other)"` line.
+const OTHER_THRESHOLD_PERCENT: f64 = 1.0;
+
+/// A node in the call tree built by `report_tree`.  `total` rolls up
+/// every descendant's value; `own_total` is just what was recorded
+/// directly under this exact stack, with nothing from its children.
+struct Node {
+    name: &'static str,
+    own_total: MValue,
+    total: MValue,
+    count: usize,
+    children: Vec<usize>,
+}
+
+/// Insert every stack path from `timings` into an arena-backed tree,
+/// roll each node's `total` up from its children, and return the arena
+/// along with the indices of its top-level (root) nodes.
+fn build_tree(timings: &Timings) -> (Vec<Node>, Vec<usize>) {
+    let mut arena: Vec<Node> = Vec::new();
+    let mut roots: Vec<usize> = Vec::new();
+    for entry in timings.entries() {
+        let mut parent: Option<usize> = None;
+        for &name in &entry.stack {
+            let siblings = match parent {
+                Some(p) => arena[p].children.clone(),
+                None => roots.clone(),
+            };
+            let idx = match siblings.iter().cloned().find(|&c| arena[c].name == name) {
+                Some(idx) => idx,
+                None => {
+                    let idx = arena.len();
+                    arena.push(Node {
+                        name,
+                        own_total: 0.0,
+                        total: 0.0,
+                        count: 0,
+                        children: Vec::new(),
+                    });
+                    match parent {
+                        Some(p) => arena[p].children.push(idx),
+                        None => roots.push(idx),
+                    }
+                    idx
+                },
+            };
+            parent = Some(idx);
+        }
+        if let Some(idx) = parent {
+            arena[idx].own_total += entry.total;
+            arena[idx].count += entry.count;
+        }
+    }
+    for &root in &roots {
+        roll_up_total(&mut arena, root);
+    }
+    (arena, roots)
+}
+
+/// Post-order: set `total` to `own_total` plus every child's (already
+/// rolled-up) `total`, and return it.
+fn roll_up_total(arena: &mut [Node], idx: usize) -> MValue {
+    let children = arena[idx].children.clone();
+    let children_total: MValue = children.iter().map(|&c| roll_up_total(arena, c)).sum();
+    arena[idx].total = arena[idx].own_total + children_total;
+    arena[idx].total
+}
+
+/// The value accrued in a node itself, as opposed to in its children.
+fn self_time(arena: &[Node], idx: usize) -> MValue {
+    arena[idx].own_total
+}
+
+fn print_node(out: &mut String, arena: &[Node], idx: usize, parent_total: f64, depth: usize) {
+    let percent = if parent_total > 0.0 {
+        100.0*arena[idx].total/parent_total
+    } else {
+        0.0
+    };
+    out.push_str(&format!("{}{:4.1}% {} {} ({}, self {})\n",
+                          "  ".repeat(depth), percent, arena[idx].name,
+                          ActiveMeasurement::format(arena[idx].total), arena[idx].count,
+                          ActiveMeasurement::format(self_time(arena, idx))));
+    print_children(out, arena, &arena[idx].children, arena[idx].total, depth + 1);
+}
+
+fn print_children(out: &mut String, arena: &[Node], children: &[usize], parent_total: f64,
+                   depth: usize) {
+    let mut sorted: Vec<usize> = children.to_vec();
+    sorted.sort_by(|&a, &b| arena[a].total.partial_cmp(&arena[b].total).unwrap());
+    sorted.reverse();
+    let mut other_total: MValue = 0.0;
+    let mut other_count = 0;
+    for c in sorted {
+        let percent = if parent_total > 0.0 {
+            100.0*arena[c].total/parent_total
+        } else {
+            0.0
+        };
+        if percent < OTHER_THRESHOLD_PERCENT {
+            other_total += arena[c].total;
+            other_count += arena[c].count;
+            continue;
+        }
+        print_node(out, arena, c, parent_total, depth);
+    }
+    if other_total > 0.0 {
+        let percent = if parent_total > 0.0 {
+            100.0*other_total/parent_total
+        } else {
+            0.0
+        };
+        out.push_str(&format!("{}{:4.1}% (other) {} ({})\n",
+                              "  ".repeat(depth), percent,
+                              ActiveMeasurement::format(other_total), other_count));
+    }
+}
+
+/// Create a string holding an indented call tree, like `report`'s flat
+/// list but showing nesting directly.  Each line shows its percentage
+/// of its parent's value, the total, the call count, and the *self*
+/// value (total minus the sum of its children) so you can tell time
+/// spent in a frame apart from time spent in its callees.  Siblings
+/// below `OTHER_THRESHOLD_PERCENT` of their parent are folded into a
+/// single `"(other)"` line.
+pub fn report_tree() -> String {
+    let t = timings();
+    let (arena, roots) = build_tree(&t);
+    let total: MValue = roots.iter().map(|&r| arena[r].total).sum();
+    let mut out = String::new();
+    print_children(&mut out, &arena, &roots, total, 0);
+    out
+}
+
+/// Thin FFI bindings to gperftools' sampling CPU profiler, used by
+/// `start_cpu_profiler`/`stop_cpu_profiler` under the `cpu_profiler`
+/// feature, so a manually-annotated region can be cross-checked against
+/// a detailed flame profile of the same code path.
+#[cfg(feature = "cpu_profiler")]
+mod cpu_profiler {
+    use std::ffi::CString;
+    use std::os::raw::{c_char, c_int};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    #[link(name = "profiler")]
+    extern "C" {
+        fn ProfilerStart(fname: *const c_char) -> c_int;
+        fn ProfilerStop();
+    }
+
+    const OFF: usize = 0;
+    const PENDING: usize = 1;
+    const ON: usize = 2;
+
+    static STATE: AtomicUsize = AtomicUsize::new(OFF);
+
+    pub fn start(path: &str) {
+        STATE.compare_exchange(OFF, PENDING, Ordering::SeqCst, Ordering::SeqCst)
+            .expect("cpu profiler is already running");
+        let cpath = CString::new(path).expect("path must not contain a NUL byte");
+        let started = unsafe { ProfilerStart(cpath.as_ptr()) } != 0;
+        if !started {
+            STATE.store(OFF, Ordering::SeqCst);
+            panic!("ProfilerStart failed");
+        }
+        STATE.store(ON, Ordering::SeqCst);
+    }
+
+    pub fn stop() {
+        if STATE.compare_exchange(ON, OFF, Ordering::SeqCst, Ordering::SeqCst).is_ok() {
+            unsafe { ProfilerStop() };
+        }
+    }
+}
+
+/// Start sampling the current process with gperftools, writing its
+/// profile to `path` on `stop_cpu_profiler`.  Combine with `push`/
+/// `profile` to get both a coarse crude-profiler table and a detailed
+/// flame profile for the same code path.
+///
+/// Panics if a profile is already running.
+#[cfg(feature = "cpu_profiler")]
+pub fn start_cpu_profiler(path: &str) {
+    cpu_profiler::start(path)
+}
+
+/// Stop a profile started by `start_cpu_profiler`.  Does nothing if no
+/// profile is running.
+#[cfg(feature = "cpu_profiler")]
+pub fn stop_cpu_profiler() {
+    cpu_profiler::stop()
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -324,4 +1073,199 @@ mod tests {
         assert!(rep.contains("second:world: 3"));
         assert!(rep.contains("world 4"));
     }
+    #[test]
+    fn concurrent_threads_do_not_corrupt_each_others_stack() {
+        let mut _m = TEST_LOCK.lock().unwrap();
+        clear();
+        let handles: Vec<_> = (0..4).map(|_| {
+            std::thread::spawn(|| {
+                for _ in 0..100 {
+                    let _a = push("worker");
+                    let _b = push("inner");
+                    std::mem::drop(_b);
+                    std::mem::drop(_a);
+                }
+            })
+        }).collect();
+        for h in handles {
+            h.join().unwrap();
+        }
+        let rep = report();
+        println!("\n{}", rep);
+        assert!(rep.contains("worker:inner"));
+        assert!(rep.contains("(400,"));
+    }
+    #[test]
+    fn dropping_a_guard_on_another_thread_panics() {
+        let mut _m = TEST_LOCK.lock().unwrap();
+        clear();
+        let guard = push("moved_task");
+        let result = std::thread::spawn(move || {
+            std::mem::drop(guard);
+        }).join();
+        assert!(result.is_err());
+        // The panic fired before the guard's frame was popped on its
+        // owning (this) thread, so `clear()` is needed to leave this
+        // thread's stack sane for whichever test runs next.
+        clear();
+    }
+    #[test]
+    fn filter_max_depth_folds_into_parent() {
+        let mut _m = TEST_LOCK.lock().unwrap();
+        clear();
+        set_filter(Filter::new().with_max_depth(1));
+        {
+            let _a = push("outer");
+            let _b = push("too deep");
+        }
+        let rep = report();
+        set_filter(Filter::default());
+        println!("\n{}", rep);
+        assert!(rep.contains("outer"));
+        assert!(!rep.contains("too deep"));
+    }
+    #[test]
+    fn filter_names_folds_into_parent() {
+        let mut _m = TEST_LOCK.lock().unwrap();
+        clear();
+        set_filter(Filter::new().with_names(vec!["outer"]));
+        {
+            let _a = push("outer");
+            let _b = push("unwanted");
+        }
+        let rep = report();
+        set_filter(Filter::default());
+        println!("\n{}", rep);
+        assert!(rep.contains("outer"));
+        assert!(!rep.contains("unwanted"));
+    }
+    #[test]
+    fn timings_exposes_structured_data() {
+        let mut _m = TEST_LOCK.lock().unwrap();
+        clear();
+        {
+            let _a = push("hello");
+            let _b = push("world");
+        }
+        let t = timings();
+        let hello_world = t.by_prefix(&["hello", "world"]);
+        assert_eq!(hello_world.len(), 1);
+        assert_eq!(hello_world[0].count, 1);
+        assert!(t.total() >= hello_world[0].total);
+        assert!(t.by_prefix(&["nope"]).is_empty());
+    }
+    #[test]
+    fn repeated_timings_on_a_still_open_task_do_not_inflate() {
+        let mut _m = TEST_LOCK.lock().unwrap();
+        clear();
+        let _a = push("long_running");
+        std::thread::sleep(std::time::Duration::from_millis(100));
+        let first = timings().by_prefix(&["long_running"])[0].total;
+        std::thread::sleep(std::time::Duration::from_millis(20));
+        let second = timings().by_prefix(&["long_running"])[0].total;
+        std::mem::drop(_a);
+        let total = timings().by_prefix(&["long_running"])[0].total;
+        // Each flush should only add the interval *since the last
+        // flush*; if it instead kept re-adding the interval since the
+        // task was first pushed, `total` would be roughly `first +
+        // second` (i.e. almost 3x the true elapsed time) rather than
+        // just a little more than `second`.
+        assert!(total < second + 0.1, "total={} second={}", total, second);
+        assert!(first < second);
+    }
+    #[test]
+    fn report_tree_shows_nesting_and_self_time() {
+        let mut _m = TEST_LOCK.lock().unwrap();
+        clear();
+        {
+            let _a = push("first");
+            std::thread::sleep(std::time::Duration::from_millis(20));
+            let _b = push("hello");
+            std::thread::sleep(std::time::Duration::from_millis(20));
+        }
+        let rep = report_tree();
+        println!("\n{}", rep);
+        assert!(rep.contains("first"));
+        assert!(rep.contains("hello"));
+        assert!(rep.contains("self"));
+        // "first"'s total should roll up "hello" underneath it (~40ms,
+        // not just its own ~20ms), while its self time should be just
+        // its own ~20ms.
+        assert!(rep.contains("100.0% first 0."));
+        assert!(!rep.contains("first 0.02"));
+    }
+    #[test]
+    fn wall_clock_formats_like_pretty_time() {
+        assert_eq!(WallClock::format(0.000_005), pretty_time(0.000_005));
+    }
+    #[test]
+    fn timings_expose_allocation_accessor() {
+        let mut _m = TEST_LOCK.lock().unwrap();
+        clear();
+        {
+            let _a = push("hello");
+            let _b = push("world");
+        }
+        let t = timings();
+        let total_allocated: isize = t.entries().iter().map(|e| e.allocated).sum();
+        assert_eq!(t.total_allocated(), total_allocated);
+    }
+    #[cfg(feature = "alloc-profiling")]
+    #[test]
+    fn allocation_is_not_attributed_across_threads() {
+        let mut _m = TEST_LOCK.lock().unwrap();
+        clear();
+        let _quiet = push("quiet_task");
+        let noisy = std::thread::spawn(|| {
+            let mut v: Vec<Vec<u8>> = Vec::new();
+            for _ in 0..50 {
+                v.push(vec![0u8; 1024 * 1024]);
+            }
+            std::hint::black_box(&v);
+        });
+        noisy.join().unwrap();
+        std::mem::drop(_quiet);
+        let t = timings();
+        let quiet = t.by_prefix(&["quiet_task"]);
+        assert_eq!(quiet.len(), 1);
+        assert!(quiet[0].allocated.abs() < 1024 * 1024);
+    }
+    #[cfg(feature = "alloc-profiling")]
+    #[test]
+    fn flushing_a_still_open_task_on_another_thread_reports_its_own_allocation() {
+        let mut _m = TEST_LOCK.lock().unwrap();
+        clear();
+        let (ready_tx, ready_rx) = std::sync::mpsc::channel();
+        let (done_tx, done_rx) = std::sync::mpsc::channel();
+        let noisy = std::thread::spawn(move || {
+            let _guard = push("noisy_task");
+            let mut v: Vec<Vec<u8>> = Vec::new();
+            for _ in 0..100 {
+                v.push(vec![0u8; 1024 * 1024]);
+            }
+            ready_tx.send(()).unwrap();
+            done_rx.recv().unwrap();
+            std::hint::black_box(&v);
+        });
+        ready_rx.recv().unwrap();
+        // Flush `noisy_task` while it's still open, from this (different)
+        // thread: its reported allocation should reflect its own ~100 MB,
+        // not garbage pulled from this thread's unrelated counter.
+        let t = timings();
+        done_tx.send(()).unwrap();
+        noisy.join().unwrap();
+        let noisy_entries = t.by_prefix(&["noisy_task"]);
+        assert_eq!(noisy_entries.len(), 1);
+        assert!(noisy_entries[0].allocated >= 99 * 1024 * 1024);
+    }
+    #[test]
+    fn profile_pushes_and_pops_around_closure() {
+        let mut _m = TEST_LOCK.lock().unwrap();
+        clear();
+        let result = profile("outer", || profile("inner", || 1 + 1));
+        assert_eq!(result, 2);
+        let rep = report();
+        println!("\n{}", rep);
+        assert!(rep.contains("outer:inner"));
+    }
 }